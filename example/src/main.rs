@@ -26,6 +26,11 @@ fn server_main() {
                 let name = lobby.name(id).unwrap();
                 lobby.message_rest(id, format!("{}: {}\n", name, msg).as_bytes());
             },
+            ScanResult::Message { data, .. } => {
+                let msg = String::from_utf8(data).unwrap();
+                let name = lobby.name(id).unwrap();
+                lobby.message_rest(id, format!("{}: {}\n", name, msg).as_bytes());
+            },
             ScanResult::IoError(err) => println!("io error: {}", err),
             ScanResult::Disconnected => println!("{} has disconnected.", lobby.name(id).unwrap()),
         });
@@ -65,7 +70,7 @@ fn client_main() {
 
             for line in lines {
                 if let Ok(line) = line {
-                    if let Err(e) = stream.write_all(line.trim_right().as_bytes()) {
+                    if let Err(e) = stream.write_all(line.trim_right().as_bytes()).and_then(|_| stream.write_all(&[b'\n'])) {
                         println!("{}", e);
                         break;
                     }
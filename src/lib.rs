@@ -21,6 +21,7 @@
 //!         match result {
 //!             ScanResult::Connected => println!("{} has connected.", name),
 //!             ScanResult::Data(data) => println!("{} sent {} bytes of data.", name, data.len()),
+//!             ScanResult::Message { data, .. } => println!("{} sent {} bytes of data.", name, data.len()),
 //!             ScanResult::IoError(err) => println!("{} ran into an IO error: {}", name, err),
 //!             ScanResult::Disconnected => println!("{} has disconnected.", name),
 //!         }
@@ -32,205 +33,168 @@
 //! thing a client should do after establishing the connection is send a UTF-8 encoded
 //! name followed by a 0 byte to indicate its termination. After that, all further
 //! data sent will be queued up to be scanned by the server.
+//!
+//! By default, each `ScanResult::Data` corresponds to exactly one `\n`-delimited
+//! line sent by the client; see `Options` if you'd rather frame messages some
+//! other way, or attach a receive timestamp and peer address to each one via
+//! `ScanResult::Message`.
+//!
+//! `Lobby` is backed by one OS thread per connection by default. For lobbies with many
+//! members, enable the `async` cargo feature to switch to a single-threaded reactor
+//! backend instead. The public API is the same either way, except that the reactor's
+//! `message` only accepts `Send + 'static` predicates and it removes a client's name before
+//! `scan()` reports that client's `ScanResult::Disconnected`.
 #![feature(collections, io, net)]
 #![allow(dead_code)]
 
 extern crate vec_map;
+#[cfg(feature = "metrics")]
+extern crate prometheus;
+#[cfg(feature = "async")]
+extern crate mio;
+
+mod metrics;
+
+#[cfg(not(feature = "async"))]
+mod threaded;
+#[cfg(feature = "async")]
+mod reactor;
+
+#[cfg(not(feature = "async"))]
+pub use threaded::Lobby;
+#[cfg(feature = "async")]
+pub use reactor::Lobby;
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+use metrics::Metrics;
+
+/// A single frame read off a client's connection, plus the metadata requested via
+/// `Options::with_timestamps` (if any).
+struct FrameData {
+    data: Vec<u8>,
+    meta: Option<(SystemTime, SocketAddr)>,
+}
 
-use std::collections::VecDeque;
-use std::io::{self, BufRead, Write, BufReader};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
-use std::thread::{self, JoinHandle};
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Receiver, TryRecvError};
+/// An error returned when a name cannot be applied to a client.
+#[derive(Debug)]
+pub enum NameError {
+    /// The requested name is already in use by another client.
+    Taken,
+}
 
-use vec_map::VecMap;
+impl std::fmt::Display for NameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            NameError::Taken => write!(f, "name is already in use"),
+        }
+    }
+}
 
-type ClientConn = (Receiver<io::Result<Vec<u8>>>, TcpStream);
+impl std::error::Error for NameError {
+    fn description(&self) -> &str {
+        match *self {
+            NameError::Taken => "name is already in use",
+        }
+    }
+}
 
-/// A Lobby server instance.
-pub struct Lobby {
-    listener: TcpListener,
-    connections: Arc<Mutex<VecMap<ClientConn>>>,
-    names: Arc<Mutex<VecMap<String>>>,
-    new_r: Receiver<usize>,
-    thread: JoinHandle<()>,
+/// Options controlling how a `Lobby` is set up.
+#[derive(Clone)]
+pub struct Options {
+    framing: Framing,
+    max_frame_size: usize,
+    name_collision: NameCollision,
+    timestamps: bool,
 }
 
-impl Lobby {
-    /// Create a new Lobby server at the specified address.
-    ///
-    /// Creating a Lobby will spawn a new thread listening for incoming connections,
-    /// plus an additional thread for each connection. The first thing any new
-    /// client should send is a UTF-8 encoded string followed by a 0 byte to indicate
-    /// its termination, which will serve as the name associated with this connection.
-    /// Note that this is not necessarily a unique identifier.
-    ///
-    /// Any additional data sent by the client will need to be processed via the `scan()`
-    /// method.
-    pub fn new<A>(addr: A) -> io::Result<Lobby> where A: ToSocketAddrs {
-        let listener = try!(TcpListener::bind(&addr));
-        let connections = Arc::new(Mutex::new(VecMap::new()));
-        let names = Arc::new(Mutex::new(VecMap::new()));
-        let (new_s, new_r) = channel();
-
-        let thread = {
-            let listener = listener.try_clone().unwrap();
-            let connections = connections.clone();
-            let names = names.clone();
-
-            thread::spawn(move || {
-                let mut id = 0;
-                let free_ids = Arc::new(Mutex::new(VecDeque::new()));
-                for conn in listener.incoming() {
-                    if let Ok(conn) = conn {
-                        let free_ids = free_ids.clone();
-                        let new_id = match free_ids.lock().unwrap().pop_front() {
-                            Some(id) => id,
-                            None => { id += 1; id },
-                        };
-
-                        let conn_reader = conn.try_clone().unwrap();
-                        let (ds, dr) = channel();
-                        let new_s = new_s.clone();
-                        let names = names.clone();
-
-                        thread::spawn(move || {
-                            let mut reader = BufReader::new(conn_reader);
-                            let mut name_buf = Vec::new();
-                            let my_id = new_id;
-
-                            match reader.read_until(0, &mut name_buf) {
-                                Ok(_) => {
-                                    name_buf.pop(); // remove the delimiting 0
-                                    names.lock().unwrap().insert(my_id, String::from_utf8(name_buf).unwrap());
-                                    new_s.send(new_id).unwrap();
-                                },
-                                Err(_) => {
-                                    drop(ds);
-                                    free_ids.lock().unwrap().push_back(my_id);
-                                    return;
-                                },
-                            }
-
-                            loop {
-                                let result = match reader.fill_buf() {
-                                    Ok(data) if data.len() == 0 => Some(0),
-                                    Ok(data) => { ds.send(Ok(data.to_vec())).unwrap(); Some(data.len()) },
-                                    Err(e) => { ds.send(Err(e)).unwrap(); None },
-                                };
-
-                                if let Some(read) = result {
-                                    if read > 0 {
-                                        reader.consume(read);
-                                    } else {
-                                        drop(ds);
-                                        free_ids.lock().unwrap().push_back(my_id);
-                                        break;
-                                    }
-                                }
-                            }
-                        });
-
-                        connections.lock().unwrap().insert(new_id, (dr, conn));
-                    }
-                }
-            })
-        };
-
-        Ok(Lobby{
-            listener: listener,
-            connections: connections,
-            names: names,
-            new_r: new_r,
-            thread: thread,
-        })
+impl Options {
+    /// Frame messages by splitting on `delim`, e.g. `b'\n'` for line-based protocols.
+    /// This is the default, using `\n` as the delimiter.
+    pub fn delimiter(mut self, delim: u8) -> Options {
+        self.framing = Framing::Delimiter(delim);
+        self
     }
 
-    /// Send a message to all connected clients.
-    ///
-    /// Returns a list of tuples pairing the id of each client that ran into an IO error with
-    /// the error itself.
-    pub fn message_all(&self, data: &[u8]) -> Vec<(usize, io::Error)> {
-        self.message(|_| true, data)
+    /// Frame messages using a 4-byte big-endian length prefix instead of a delimiter.
+    pub fn length_prefixed(mut self) -> Options {
+        self.framing = Framing::LengthPrefixed;
+        self
     }
 
-    /// Send a message to a single client.
-    ///
-    /// Returns a list of tuples pairing the id of each client that ran into an IO error with
-    /// the error itself.
-    pub fn message_client(&self, client: usize, data: &[u8]) -> Vec<(usize, io::Error)> {
-        self.message(|id| id == client, data)
+    /// Set the largest frame a client may send before the connection is closed, to keep
+    /// a misbehaving client from making the server buffer unbounded data. Defaults to 64KiB.
+    pub fn max_frame_size(mut self, size: usize) -> Options {
+        self.max_frame_size = size;
+        self
     }
 
-    /// Send a message to every client but one. Useful for, e.g., one client messaging the others.
-    ///
-    /// Returns a list of tuples pairing the id of each client that ran into an IO error with
-    /// the error itself.
-    pub fn message_rest(&self, client: usize, data: &[u8]) -> Vec<(usize, io::Error)> {
-        self.message(|id| id != client, data)
+    /// Close a client's connection during the handshake if it registers a name that's
+    /// already in use. By default duplicate names are allowed.
+    pub fn reject_duplicate_names(mut self) -> Options {
+        self.name_collision = NameCollision::Close;
+        self
     }
 
-    /// Send a message to every connected client for which `predicate` returns true.
-    ///
-    /// Returns a list of tuples pairing the id of each client that ran into an IO error with
-    /// the error itself.
-    pub fn message<P>(&self, predicate: P, data: &[u8]) -> Vec<(usize, io::Error)> where P: Fn(usize) -> bool {
-        let mut failed = Vec::new();
-        for (id, &mut (_, ref mut conn)) in self.connections.lock().unwrap().iter_mut().filter(|&(id, _)| predicate(id)) {
-            if let Err(e) = conn.write_all(data) {
-                failed.push((id, e));
-            }
-        }
-        failed
+    /// If a client registers a name that's already in use, send it `byte` and let it try
+    /// again with a different name instead of closing the connection.
+    pub fn reject_duplicate_names_with(mut self, byte: u8) -> Options {
+        self.name_collision = NameCollision::Reject(byte);
+        self
     }
 
-    /// Scan the clients' message queues for data.
-    ///
-    /// Note that the callback is only invoked if there is something to report, and that
-    /// this method does not block. Most applications will want to wrap this call up
-    /// in their main loop in order to continuously process data.
-    pub fn scan<F: Fn(usize, ScanResult) -> ()>(&self, callback: F) {
-        loop {
-            match self.new_r.try_recv() {
-                Ok(id) => callback(id, ScanResult::Connected),
-                Err(e) if e == TryRecvError::Empty => break,
-                Err(e) if e == TryRecvError::Disconnected => {
-                    panic!("tried to check for new clients on disconnected channel!");
-                },
-                Err(_) => unimplemented!(),
-            }
-        }
-
-        let mut results = Vec::with_capacity(self.connections.lock().unwrap().len());
-
-        for (id, &mut (ref mut dr, _)) in self.connections.lock().unwrap().iter_mut() {
-            match dr.try_recv() {
-                Ok(Ok(data)) => results.push((id, ScanResult::Data(data))),
-                Ok(Err(err)) => results.push((id, ScanResult::IoError(err))),
-                Err(TryRecvError::Empty) => {}, // do nothing
-                Err(TryRecvError::Disconnected) => results.push((id, ScanResult::Disconnected)),
-            }
-        }
+    /// Attach the receive time and peer address to every frame, delivered as
+    /// `ScanResult::Message` instead of `ScanResult::Data`.
+    pub fn with_timestamps(mut self) -> Options {
+        self.timestamps = true;
+        self
+    }
+}
 
-        for (id, result) in results.into_iter() {
-            if let ScanResult::Disconnected = result {
-                self.connections.lock().unwrap().remove(id);
-            }
-            callback(id, result);
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            framing: Framing::Delimiter(b'\n'),
+            max_frame_size: 64 * 1024,
+            name_collision: NameCollision::Allow,
+            timestamps: false,
         }
     }
+}
 
-    /// Get the registered name for a given client.
-    pub fn name(&self, client: usize) -> Option<String> {
-        self.names.lock().unwrap().get(client).map(|s| s.clone())
-    }
+#[derive(Clone, Copy)]
+enum Framing {
+    Delimiter(u8),
+    LengthPrefixed,
+}
+
+/// How the handshake should react when a client registers a name that's already taken.
+#[derive(Clone, Copy)]
+enum NameCollision {
+    /// Allow the duplicate name; this is the default.
+    Allow,
+    /// Close the connection.
+    Close,
+    /// Send the client this byte and let it retry with a different name.
+    Reject(u8),
 }
 
 /// The result of a client scan.
 pub enum ScanResult {
     /// The client sent data.
     Data(Vec<u8>),
+    /// The client sent data, timestamped with when it was received; delivered instead of
+    /// `Data` when the Lobby was created with `Options::with_timestamps`.
+    Message {
+        /// The frame's contents.
+        data: Vec<u8>,
+        /// When this frame finished being read off the socket.
+        received_at: SystemTime,
+        /// The client's address.
+        addr: SocketAddr,
+    },
     /// An IO error occurred while scanning.
     IoError(io::Error),
     /// A new client has connected.
@@ -0,0 +1,88 @@
+//! Prometheus instrumentation for a `Lobby`, enabled via the `metrics` cargo feature.
+//!
+//! With the feature disabled, `Metrics` is a zero-cost no-op so the core crate stays
+//! free of the `prometheus` dependency.
+
+#[cfg(feature = "metrics")]
+mod imp {
+    use prometheus::{IntCounter, IntGauge, Opts, Registry};
+
+    /// A Lobby's connection and traffic metrics.
+    ///
+    /// Register these with your own `prometheus::Registry` via `register()` so they're
+    /// exported alongside the rest of your application's metrics.
+    #[derive(Clone)]
+    pub struct Metrics {
+        /// Number of clients currently connected.
+        pub connections: IntGauge,
+        /// Total number of connections accepted over the lifetime of the lobby.
+        pub connections_total: IntCounter,
+        /// Total bytes received from clients.
+        pub bytes_received: IntCounter,
+        /// Total bytes sent to clients.
+        pub bytes_sent: IntCounter,
+    }
+
+    impl Metrics {
+        pub fn new() -> Metrics {
+            Metrics {
+                connections: IntGauge::new("lobby_connections", "Clients currently connected").unwrap(),
+                connections_total: IntCounter::with_opts(
+                    Opts::new("lobby_connections_total", "Total connections accepted")
+                ).unwrap(),
+                bytes_received: IntCounter::with_opts(
+                    Opts::new("lobby_bytes_received_total", "Total bytes received from clients")
+                ).unwrap(),
+                bytes_sent: IntCounter::with_opts(
+                    Opts::new("lobby_bytes_sent_total", "Total bytes sent to clients")
+                ).unwrap(),
+            }
+        }
+
+        /// Register this lobby's collectors into `registry`.
+        pub fn register(&self, registry: &Registry) -> Result<(), prometheus::Error> {
+            try!(registry.register(Box::new(self.connections.clone())));
+            try!(registry.register(Box::new(self.connections_total.clone())));
+            try!(registry.register(Box::new(self.bytes_received.clone())));
+            try!(registry.register(Box::new(self.bytes_sent.clone())));
+            Ok(())
+        }
+
+        pub fn inc_connections(&self) {
+            self.connections.inc();
+            self.connections_total.inc();
+        }
+
+        pub fn dec_connections(&self) {
+            self.connections.dec();
+        }
+
+        pub fn add_received(&self, bytes: usize) {
+            self.bytes_received.inc_by(bytes as i64);
+        }
+
+        pub fn add_sent(&self, bytes: usize) {
+            self.bytes_sent.inc_by(bytes as i64);
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod imp {
+    /// No-op metrics used when the `metrics` feature is disabled.
+    #[derive(Clone, Default)]
+    pub struct Metrics;
+
+    impl Metrics {
+        pub fn new() -> Metrics {
+            Metrics
+        }
+
+        pub fn inc_connections(&self) {}
+        pub fn dec_connections(&self) {}
+        pub fn add_received(&self, _bytes: usize) {}
+        pub fn add_sent(&self, _bytes: usize) {}
+    }
+}
+
+pub use self::imp::Metrics;
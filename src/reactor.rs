@@ -0,0 +1,423 @@
+//! An alternative `Lobby` backend, enabled via the `async` cargo feature, that multiplexes
+//! every connection through a single `mio` event loop instead of spawning a thread per
+//! client. The public surface matches the default `threaded` backend, except that `message`
+//! only accepts `Send + 'static` predicates and a client's name is already removed by the
+//! time `scan()` reports its `ScanResult::Disconnected`.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+
+use vec_map::VecMap;
+
+use mio::{Poll, Events, Token, PollOpt, Ready, Registration, SetReadiness};
+use mio::tcp::{TcpListener, TcpStream};
+
+use {Options, Framing, NameCollision, NameError, ScanResult, Metrics};
+
+const LISTENER: Token = Token(0);
+/// Token for the `Registration` that wakes the reactor up whenever a command is queued, so
+/// `Lobby::message`/`disconnect` don't have to wait for unrelated socket traffic to make
+/// progress. Connection tokens start at `Token(id + 1)` with `id >= 1`, so this is unused.
+const COMMANDS: Token = Token(1);
+const READ_BUF_SIZE: usize = 4096;
+
+/// Commands the public `Lobby` handle sends to the reactor thread, which owns every
+/// socket and so is the only thing allowed to touch them.
+enum Command {
+    Message(Box<Fn(usize) -> bool + Send>, Vec<u8>, Sender<Vec<(usize, io::Error)>>),
+    Disconnect(usize),
+}
+
+/// Per-connection state, owned entirely by the reactor thread.
+struct Client {
+    stream: TcpStream,
+    addr: SocketAddr,
+    name: Option<String>,
+    pending: Vec<u8>,
+}
+
+/// A Lobby server instance backed by a single-threaded `mio` reactor.
+pub struct Lobby {
+    names: Arc<Mutex<VecMap<String>>>,
+    events_r: Receiver<(usize, ScanResult)>,
+    cmd_s: Sender<Command>,
+    cmd_readiness: SetReadiness,
+    metrics: Metrics,
+    thread: JoinHandle<()>,
+}
+
+impl Lobby {
+    /// Create a new Lobby server at the specified address, using the default
+    /// `\n`-delimited framing.
+    pub fn new<A>(addr: A) -> io::Result<Lobby> where A: ToSocketAddrs {
+        Lobby::with_options(addr, Options::default())
+    }
+
+    /// Create a new Lobby server at the specified address using the given `Options`.
+    pub fn with_options<A>(addr: A, options: Options) -> io::Result<Lobby> where A: ToSocketAddrs {
+        let addr = try!(try!(addr.to_socket_addrs()).next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no socket addresses resolved")
+        }));
+
+        let listener = try!(TcpListener::bind(&addr));
+        let poll = try!(Poll::new());
+        try!(poll.register(&listener, LISTENER, Ready::readable(), PollOpt::edge()));
+
+        let (registration, cmd_readiness) = Registration::new2();
+        try!(poll.register(&registration, COMMANDS, Ready::readable(), PollOpt::edge()));
+
+        let names = Arc::new(Mutex::new(VecMap::new()));
+        let (events_s, events_r) = channel();
+        let (cmd_s, cmd_r) = channel();
+        let metrics = Metrics::new();
+
+        let thread = {
+            let names = names.clone();
+            let options = options.clone();
+            let metrics = metrics.clone();
+
+            thread::spawn(move || {
+                run_reactor(listener, poll, registration, options, names, metrics, events_s, cmd_r);
+            })
+        };
+
+        Ok(Lobby {
+            names: names,
+            events_r: events_r,
+            cmd_s: cmd_s,
+            cmd_readiness: cmd_readiness,
+            metrics: metrics,
+            thread: thread,
+        })
+    }
+
+    /// Forcibly disconnect a client, e.g. to kick them from the lobby. This only queues the
+    /// request for the reactor thread and returns without waiting for it; the removal is
+    /// reported via `ScanResult::Disconnected` by the first `scan()` call made after the
+    /// reactor thread has handled it.
+    pub fn disconnect(&self, client: usize) {
+        let _ = self.send_command(Command::Disconnect(client));
+    }
+
+    /// Queue `cmd` for the reactor thread and wake it up immediately, rather than letting it
+    /// sit until unrelated socket traffic happens to bring the reactor back around to its
+    /// command-draining step. Returns whether the command was actually queued.
+    fn send_command(&self, cmd: Command) -> bool {
+        if self.cmd_s.send(cmd).is_err() {
+            return false;
+        }
+        let _ = self.cmd_readiness.set_readiness(Ready::readable());
+        true
+    }
+
+    /// Get this lobby's Prometheus metrics, so they can be registered into your own
+    /// `prometheus::Registry`. Only available when the `metrics` cargo feature is enabled.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Send a message to all connected clients.
+    pub fn message_all(&self, data: &[u8]) -> Vec<(usize, io::Error)> {
+        self.message(|_| true, data)
+    }
+
+    /// Send a message to a single client.
+    pub fn message_client(&self, client: usize, data: &[u8]) -> Vec<(usize, io::Error)> {
+        self.message(move |id| id == client, data)
+    }
+
+    /// Send a message to every client but one.
+    pub fn message_rest(&self, client: usize, data: &[u8]) -> Vec<(usize, io::Error)> {
+        self.message(move |id| id != client, data)
+    }
+
+    /// Send a message to every connected client for which `predicate` returns true.
+    pub fn message<P>(&self, predicate: P, data: &[u8]) -> Vec<(usize, io::Error)> where P: Fn(usize) -> bool + Send + 'static {
+        let (reply_s, reply_r) = channel();
+        if !self.send_command(Command::Message(Box::new(predicate), data.to_vec(), reply_s)) {
+            return Vec::new();
+        }
+        reply_r.recv().unwrap_or_else(|_| Vec::new())
+    }
+
+    /// Broadcast a message to all connected clients, pruning any whose connection has gone
+    /// bad instead of letting them linger. Returns the ids of the clients that were pruned;
+    /// each is surfaced once more as a `ScanResult::Disconnected` by a later `scan()` call,
+    /// as with `disconnect()`.
+    pub fn broadcast_pruning(&self, data: &[u8]) -> Vec<usize> {
+        self.message_all(data).into_iter()
+            .map(|(id, _)| { self.disconnect(id); id })
+            .collect()
+    }
+
+    /// Scan the clients' message queues for data.
+    ///
+    /// Note that the callback is only invoked if there is something to report, and that
+    /// this method does not block.
+    pub fn scan<F: Fn(usize, ScanResult) -> ()>(&self, callback: F) {
+        loop {
+            match self.events_r.try_recv() {
+                Ok((id, result)) => callback(id, result),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    panic!("tried to scan a lobby whose reactor thread has died!");
+                },
+            }
+        }
+    }
+
+    /// Get the registered name for a given client.
+    pub fn name(&self, client: usize) -> Option<String> {
+        self.names.lock().unwrap().get(client).map(|s| s.clone())
+    }
+
+    /// Change a connected client's name.
+    pub fn rename(&self, client: usize, new_name: String) -> Result<(), NameError> {
+        let mut names = self.names.lock().unwrap();
+        if names.iter().any(|(id, name)| id != client && name == &new_name) {
+            return Err(NameError::Taken);
+        }
+        names.insert(client, new_name);
+        Ok(())
+    }
+
+    /// Look up a connected client's id by their registered name.
+    pub fn id_by_name(&self, name: &str) -> Option<usize> {
+        self.names.lock().unwrap().iter().find(|&(_, n)| n == name).map(|(id, _)| id)
+    }
+}
+
+/// Drives every client socket from a single reactor loop: accepts new connections,
+/// handles the name handshake, frames incoming data, and executes commands from the
+/// public `Lobby` handle. Runs until `poll` returns an error.
+///
+/// `_commands_registration` is never read directly; it just needs to stay alive for as long
+/// as `poll` does, since dropping it would deregister the `SetReadiness` that wakes this loop
+/// up whenever `Lobby::message`/`disconnect` queue a command.
+fn run_reactor(listener: TcpListener, poll: Poll, _commands_registration: Registration, options: Options,
+                names: Arc<Mutex<VecMap<String>>>, metrics: Metrics, events_s: Sender<(usize, ScanResult)>,
+                cmd_r: Receiver<Command>) {
+    let mut clients: VecMap<Client> = VecMap::new();
+    let mut free_ids: VecDeque<usize> = VecDeque::new();
+    let mut next_id = 0usize;
+    let mut events = Events::with_capacity(1024);
+    let mut read_buf = [0u8; READ_BUF_SIZE];
+
+    loop {
+        if poll.poll(&mut events, None).is_err() {
+            return;
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => {
+                    while let Ok((stream, addr)) = listener.accept() {
+                        let id = free_ids.pop_front().unwrap_or_else(|| { next_id += 1; next_id });
+                        if poll.register(&stream, Token(id + 1), Ready::readable(), PollOpt::edge()).is_ok() {
+                            clients.insert(id, Client { stream: stream, addr: addr, name: None, pending: Vec::new() });
+                            metrics.inc_connections();
+                        }
+                    }
+                },
+                COMMANDS => {}, // just here to wake `poll`; commands are drained below every tick
+                Token(t) => {
+                    let id = t - 1;
+                    handle_readable(id, &mut clients, &names, &options, &metrics, &mut free_ids, &events_s, &mut read_buf);
+                },
+            }
+        }
+
+        while let Ok(cmd) = cmd_r.try_recv() {
+            match cmd {
+                Command::Message(predicate, data, reply) => {
+                    let mut failed = Vec::new();
+                    for (id, client) in clients.iter_mut().filter(|&(id, _)| predicate(id)) {
+                        // `client.stream` is non-blocking, so a momentarily full send buffer
+                        // surfaces as `WouldBlock` rather than a real failure; treat it as
+                        // "not sent this round" instead of reporting (and later pruning) a
+                        // perfectly healthy client.
+                        match client.stream.write_all(&data) {
+                            Ok(()) => metrics.add_sent(data.len()),
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                            Err(e) => failed.push((id, e)),
+                        }
+                    }
+                    let _ = reply.send(failed);
+                },
+                Command::Disconnect(id) => {
+                    prune(id, &mut clients, &names, &metrics, &mut free_ids, &events_s);
+                },
+            }
+        }
+    }
+}
+
+/// Reads whatever is currently available for `id` into the reusable `read_buf`, appends it
+/// to that client's pending bytes, and emits as many complete frames as are now buffered.
+fn handle_readable(id: usize, clients: &mut VecMap<Client>, names: &Arc<Mutex<VecMap<String>>>, options: &Options,
+                    metrics: &Metrics, free_ids: &mut VecDeque<usize>, events_s: &Sender<(usize, ScanResult)>,
+                    read_buf: &mut [u8]) {
+    loop {
+        let read_result = match clients.get_mut(id) {
+            Some(client) => client.stream.read(read_buf),
+            None => return,
+        };
+
+        match read_result {
+            Ok(0) => {
+                prune(id, clients, names, metrics, free_ids, events_s);
+                return;
+            },
+            Ok(n) => {
+                metrics.add_received(n);
+                clients.get_mut(id).unwrap().pending.extend_from_slice(&read_buf[..n]);
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(e) => {
+                let _ = events_s.send((id, ScanResult::IoError(e)));
+                prune(id, clients, names, metrics, free_ids, events_s);
+                return;
+            },
+        }
+
+        loop {
+            let handshaking = clients.get(id).unwrap().name.is_none();
+
+            let taken = if handshaking {
+                // The handshake always reads a name up to a literal 0 byte, regardless of
+                // `options.framing`, matching the threaded backend and the protocol docs.
+                take_delimited(&mut clients.get_mut(id).unwrap().pending, 0, options.max_frame_size)
+            } else {
+                take_frame(clients.get_mut(id).unwrap(), options)
+            };
+
+            let frame = match taken {
+                TakeFrame::Frame(frame) => frame,
+                TakeFrame::Incomplete => break,
+                TakeFrame::TooLarge => {
+                    prune(id, clients, names, metrics, free_ids, events_s);
+                    return;
+                },
+            };
+
+            if handshaking {
+                let outcome = apply_name(id, frame, names, options, clients.get_mut(id).unwrap());
+                match outcome {
+                    Handshake::Accepted => { let _ = events_s.send((id, ScanResult::Connected)); },
+                    Handshake::Retry => continue, // wait for the client to send another name
+                    Handshake::Abort => {
+                        prune(id, clients, names, metrics, free_ids, events_s);
+                        return;
+                    },
+                }
+                continue;
+            }
+
+            let addr = clients.get(id).unwrap().addr;
+            let result = if options.timestamps {
+                ScanResult::Message { data: frame, received_at: SystemTime::now(), addr: addr }
+            } else {
+                ScanResult::Data(frame)
+            };
+            let _ = events_s.send((id, result));
+        }
+    }
+}
+
+/// The outcome of applying a handshake frame as a client's name.
+enum Handshake {
+    Accepted,
+    /// The name collided and the client was asked to retry.
+    Retry,
+    Abort,
+}
+
+/// The outcome of trying to pull one frame out of a client's pending bytes.
+enum TakeFrame {
+    Frame(Vec<u8>),
+    /// Not enough bytes are buffered yet for a complete frame.
+    Incomplete,
+    /// The frame would exceed `options.max_frame_size` before completing.
+    TooLarge,
+}
+
+/// Pulls one `delim`-terminated frame out of `pending`, giving up once the frame is longer
+/// than `max_frame_size`, whether or not its delimiter has shown up yet.
+fn take_delimited(pending: &mut Vec<u8>, delim: u8, max_frame_size: usize) -> TakeFrame {
+    match pending.iter().position(|&b| b == delim) {
+        Some(pos) if pos > max_frame_size => TakeFrame::TooLarge,
+        Some(pos) => {
+            let frame = pending.drain(..pos).collect();
+            pending.remove(0); // drop the delimiter itself
+            TakeFrame::Frame(frame)
+        },
+        None if pending.len() > max_frame_size => TakeFrame::TooLarge,
+        None => TakeFrame::Incomplete,
+    }
+}
+
+/// Pulls one complete frame out of `client.pending` according to `options.framing`, if one
+/// is fully buffered yet, enforcing `options.max_frame_size` along the way.
+fn take_frame(client: &mut Client, options: &Options) -> TakeFrame {
+    match options.framing {
+        Framing::Delimiter(delim) => take_delimited(&mut client.pending, delim, options.max_frame_size),
+        Framing::LengthPrefixed => {
+            if client.pending.len() < 4 {
+                return TakeFrame::Incomplete;
+            }
+            let len = ((client.pending[0] as usize) << 24) | ((client.pending[1] as usize) << 16) |
+                      ((client.pending[2] as usize) << 8) | (client.pending[3] as usize);
+            if len > options.max_frame_size {
+                return TakeFrame::TooLarge;
+            }
+            if client.pending.len() < 4 + len {
+                return TakeFrame::Incomplete;
+            }
+            client.pending.drain(..4);
+            TakeFrame::Frame(client.pending.drain(..len).collect())
+        },
+    }
+}
+
+/// Applies the handshake frame as this client's name, honoring `options.name_collision`.
+fn apply_name(id: usize, frame: Vec<u8>, names: &Arc<Mutex<VecMap<String>>>, options: &Options, client: &mut Client) -> Handshake {
+    let name = match String::from_utf8(frame) {
+        Ok(name) => name,
+        Err(_) => return Handshake::Abort,
+    };
+
+    let mut names = names.lock().unwrap();
+    if names.values().any(|existing| existing == &name) {
+        match options.name_collision {
+            NameCollision::Close => return Handshake::Abort,
+            NameCollision::Reject(byte) => {
+                let _ = client.stream.write_all(&[byte]);
+                return Handshake::Retry;
+            },
+            NameCollision::Allow => {},
+        }
+    }
+
+    names.insert(id, name.clone());
+    client.name = Some(name);
+    Handshake::Accepted
+}
+
+/// Tears down a client's connection and notifies `scan()` of its removal.
+fn prune(id: usize, clients: &mut VecMap<Client>, names: &Arc<Mutex<VecMap<String>>>, metrics: &Metrics,
+         free_ids: &mut VecDeque<usize>, events_s: &Sender<(usize, ScanResult)>) {
+    if clients.remove(id).is_some() {
+        names.lock().unwrap().remove(id);
+        if !free_ids.contains(&id) {
+            free_ids.push_back(id);
+        }
+        metrics.dec_connections();
+        let _ = events_s.send((id, ScanResult::Disconnected));
+    }
+}
@@ -0,0 +1,440 @@
+//! The default `Lobby` backend: one OS thread accepting connections, plus one more per
+//! connected client. Simple and battle-tested, but doesn't scale past a few hundred
+//! clients; see `reactor` (behind the `async` feature) for a single-threaded alternative.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, BufRead, Write, BufReader};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread::{self, JoinHandle};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+use std::time::SystemTime;
+
+use vec_map::VecMap;
+
+use {FrameData, Options, Framing, NameCollision, NameError, ScanResult, Metrics};
+
+type ClientConn = (Receiver<io::Result<FrameData>>, TcpStream);
+
+/// A Lobby server instance.
+pub struct Lobby {
+    listener: TcpListener,
+    connections: Arc<Mutex<VecMap<ClientConn>>>,
+    names: Arc<Mutex<VecMap<String>>>,
+    free_ids: Arc<Mutex<VecDeque<usize>>>,
+    new_r: Receiver<usize>,
+    disconnected_s: Sender<usize>,
+    disconnected_r: Receiver<usize>,
+    metrics: Metrics,
+    thread: JoinHandle<()>,
+}
+
+impl Lobby {
+    /// Create a new Lobby server at the specified address, using the default
+    /// `\n`-delimited framing.
+    ///
+    /// Creating a Lobby will spawn a new thread listening for incoming connections,
+    /// plus an additional thread for each connection. The first thing any new
+    /// client should send is a UTF-8 encoded string followed by a 0 byte to indicate
+    /// its termination, which will serve as the name associated with this connection.
+    /// Note that this is not necessarily a unique identifier.
+    ///
+    /// Any additional data sent by the client will need to be processed via the `scan()`
+    /// method.
+    pub fn new<A>(addr: A) -> io::Result<Lobby> where A: ToSocketAddrs {
+        Lobby::with_options(addr, Options::default())
+    }
+
+    /// Create a new Lobby server at the specified address using the given `Options`,
+    /// e.g. to choose how incoming data is framed into messages.
+    pub fn with_options<A>(addr: A, options: Options) -> io::Result<Lobby> where A: ToSocketAddrs {
+        let listener = try!(TcpListener::bind(&addr));
+        let connections = Arc::new(Mutex::new(VecMap::new()));
+        let names = Arc::new(Mutex::new(VecMap::new()));
+        let free_ids = Arc::new(Mutex::new(VecDeque::new()));
+        let (new_s, new_r) = channel();
+        let (disconnected_s, disconnected_r) = channel();
+        let metrics = Metrics::new();
+
+        let thread = {
+            let listener = listener.try_clone().unwrap();
+            let connections = connections.clone();
+            let names = names.clone();
+            let options = options.clone();
+            let free_ids = free_ids.clone();
+            let metrics = metrics.clone();
+
+            thread::spawn(move || {
+                let mut id = 0;
+                for conn in listener.incoming() {
+                    if let Ok(conn) = conn {
+                        let free_ids = free_ids.clone();
+                        let new_id = match free_ids.lock().unwrap().pop_front() {
+                            Some(id) => id,
+                            None => { id += 1; id },
+                        };
+
+                        let conn_reader = conn.try_clone().unwrap();
+                        let (ds, dr) = channel();
+                        let new_s = new_s.clone();
+                        let names = names.clone();
+                        let options = options.clone();
+                        let metrics = metrics.clone();
+
+                        thread::spawn(move || {
+                            let writer = conn_reader.try_clone().unwrap();
+                            let mut reader = BufReader::new(conn_reader);
+                            let my_id = new_id;
+
+                            if !perform_handshake(&mut reader, writer, &names, my_id, &options) {
+                                drop(ds);
+                                recycle_id(&free_ids, my_id);
+                                return;
+                            }
+                            new_s.send(new_id).unwrap();
+
+                            read_frames(&mut reader, &ds, &options, &metrics);
+                            drop(ds);
+                            recycle_id(&free_ids, my_id);
+                        });
+
+                        connections.lock().unwrap().insert(new_id, (dr, conn));
+                        metrics.inc_connections();
+                    }
+                }
+            })
+        };
+
+        Ok(Lobby{
+            listener: listener,
+            connections: connections,
+            names: names,
+            free_ids: free_ids,
+            new_r: new_r,
+            disconnected_s: disconnected_s,
+            disconnected_r: disconnected_r,
+            metrics: metrics,
+            thread: thread,
+        })
+    }
+
+    /// Forcibly disconnect a client, e.g. to kick them from the lobby.
+    ///
+    /// This shuts down the client's socket, removes it from the lobby's bookkeeping, and
+    /// recycles its id so it can be handed out to a future connection. The next call to
+    /// `scan()` will report the removal via `ScanResult::Disconnected`.
+    pub fn disconnect(&self, client: usize) {
+        self.prune(client);
+    }
+
+    /// Tear down a client's connection and notify the next `scan()` of its removal.
+    /// Returns whether the client was actually connected.
+    fn prune(&self, client: usize) -> bool {
+        let conn = self.connections.lock().unwrap().remove(client);
+        self.names.lock().unwrap().remove(client);
+
+        match conn {
+            Some((_, conn)) => {
+                let _ = conn.shutdown(Shutdown::Both);
+                recycle_id(&self.free_ids, client);
+                self.metrics.dec_connections();
+                self.disconnected_s.send(client).unwrap();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Get this lobby's Prometheus metrics, so they can be registered into your own
+    /// `prometheus::Registry`. Only available when the `metrics` cargo feature is enabled.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Send a message to all connected clients.
+    ///
+    /// Returns a list of tuples pairing the id of each client that ran into an IO error with
+    /// the error itself.
+    pub fn message_all(&self, data: &[u8]) -> Vec<(usize, io::Error)> {
+        self.message(|_| true, data)
+    }
+
+    /// Send a message to a single client.
+    ///
+    /// Returns a list of tuples pairing the id of each client that ran into an IO error with
+    /// the error itself.
+    pub fn message_client(&self, client: usize, data: &[u8]) -> Vec<(usize, io::Error)> {
+        self.message(|id| id == client, data)
+    }
+
+    /// Send a message to every client but one. Useful for, e.g., one client messaging the others.
+    ///
+    /// Returns a list of tuples pairing the id of each client that ran into an IO error with
+    /// the error itself.
+    pub fn message_rest(&self, client: usize, data: &[u8]) -> Vec<(usize, io::Error)> {
+        self.message(|id| id != client, data)
+    }
+
+    /// Send a message to every connected client for which `predicate` returns true.
+    ///
+    /// Returns a list of tuples pairing the id of each client that ran into an IO error with
+    /// the error itself.
+    pub fn message<P>(&self, predicate: P, data: &[u8]) -> Vec<(usize, io::Error)> where P: Fn(usize) -> bool {
+        let mut failed = Vec::new();
+        for (id, &mut (_, ref mut conn)) in self.connections.lock().unwrap().iter_mut().filter(|&(id, _)| predicate(id)) {
+            match conn.write_all(data) {
+                Ok(()) => self.metrics.add_sent(data.len()),
+                Err(e) => failed.push((id, e)),
+            }
+        }
+        failed
+    }
+
+    /// Broadcast a message to all connected clients, pruning any whose connection has gone
+    /// bad instead of letting them linger until the reader thread's EOF trickles through
+    /// `scan()`.
+    ///
+    /// Returns the ids of the clients that were pruned; each is surfaced once more as a
+    /// `ScanResult::Disconnected` on the next `scan()` call.
+    pub fn broadcast_pruning(&self, data: &[u8]) -> Vec<usize> {
+        self.message_all(data).into_iter()
+            .filter_map(|(id, _)| if self.prune(id) { Some(id) } else { None })
+            .collect()
+    }
+
+    /// Scan the clients' message queues for data.
+    ///
+    /// Note that the callback is only invoked if there is something to report, and that
+    /// this method does not block. Most applications will want to wrap this call up
+    /// in their main loop in order to continuously process data.
+    pub fn scan<F: Fn(usize, ScanResult) -> ()>(&self, callback: F) {
+        loop {
+            match self.new_r.try_recv() {
+                Ok(id) => callback(id, ScanResult::Connected),
+                Err(e) if e == TryRecvError::Empty => break,
+                Err(e) if e == TryRecvError::Disconnected => {
+                    panic!("tried to check for new clients on disconnected channel!");
+                },
+                Err(_) => unimplemented!(),
+            }
+        }
+
+        loop {
+            match self.disconnected_r.try_recv() {
+                Ok(id) => callback(id, ScanResult::Disconnected),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    panic!("tried to check for forced disconnects on disconnected channel!");
+                },
+            }
+        }
+
+        let mut results = Vec::with_capacity(self.connections.lock().unwrap().len());
+
+        for (id, &mut (ref mut dr, _)) in self.connections.lock().unwrap().iter_mut() {
+            match dr.try_recv() {
+                Ok(Ok(frame)) => {
+                    let result = match frame.meta {
+                        Some((received_at, addr)) => ScanResult::Message { data: frame.data, received_at: received_at, addr: addr },
+                        None => ScanResult::Data(frame.data),
+                    };
+                    results.push((id, result));
+                },
+                Ok(Err(err)) => results.push((id, ScanResult::IoError(err))),
+                Err(TryRecvError::Empty) => {}, // do nothing
+                Err(TryRecvError::Disconnected) => results.push((id, ScanResult::Disconnected)),
+            }
+        }
+
+        for (id, result) in results.into_iter() {
+            let disconnected = if let ScanResult::Disconnected = result { true } else { false };
+            if disconnected {
+                self.connections.lock().unwrap().remove(id);
+                self.metrics.dec_connections();
+            }
+            callback(id, result);
+            if disconnected {
+                // Dropped after the callback so it can still look up the departing client's name.
+                self.names.lock().unwrap().remove(id);
+            }
+        }
+    }
+
+    /// Get the registered name for a given client.
+    pub fn name(&self, client: usize) -> Option<String> {
+        self.names.lock().unwrap().get(client).map(|s| s.clone())
+    }
+
+    /// Change a connected client's name.
+    ///
+    /// Fails with `NameError::Taken` if another client is already using `new_name`, leaving
+    /// the client's existing name untouched.
+    pub fn rename(&self, client: usize, new_name: String) -> Result<(), NameError> {
+        let mut names = self.names.lock().unwrap();
+        if names.iter().any(|(id, name)| id != client && name == &new_name) {
+            return Err(NameError::Taken);
+        }
+        names.insert(client, new_name);
+        Ok(())
+    }
+
+    /// Look up a connected client's id by their registered name.
+    pub fn id_by_name(&self, name: &str) -> Option<usize> {
+        self.names.lock().unwrap().iter().find(|&(_, n)| n == name).map(|(id, _)| id)
+    }
+}
+
+/// Performs the client name handshake: reads a 0-terminated name from `reader`, applying
+/// `options.name_collision` if it collides with an existing client's name. Returns `true`
+/// and registers the name under `my_id` on success, `false` if the connection should be
+/// abandoned.
+fn perform_handshake(reader: &mut BufReader<TcpStream>, mut writer: TcpStream, names: &Arc<Mutex<VecMap<String>>>, my_id: usize, options: &Options) -> bool {
+    loop {
+        let mut name_buf = Vec::new();
+        match reader.read_until(0, &mut name_buf) {
+            Ok(0) => return false,
+            Ok(_) => {
+                name_buf.pop(); // remove the delimiting 0
+                let name = match String::from_utf8(name_buf) {
+                    Ok(name) => name,
+                    Err(_) => return false,
+                };
+                let mut names = names.lock().unwrap();
+                let taken = names.values().any(|existing| existing == &name);
+
+                if taken {
+                    if let NameCollision::Reject(byte) = options.name_collision {
+                        drop(names);
+                        if writer.write_all(&[byte]).is_err() {
+                            return false;
+                        }
+                        continue;
+                    }
+                    if let NameCollision::Close = options.name_collision {
+                        return false;
+                    }
+                }
+
+                names.insert(my_id, name);
+                return true;
+            },
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Push `id` onto the free-id pool, unless it's already there. A client can have its id
+/// recycled both by `Lobby::disconnect` and by its own reader thread noticing the socket
+/// was shut down, so this guards against handing the same id out twice.
+fn recycle_id(free_ids: &Arc<Mutex<VecDeque<usize>>>, id: usize) {
+    let mut free_ids = free_ids.lock().unwrap();
+    if !free_ids.contains(&id) {
+        free_ids.push_back(id);
+    }
+}
+
+/// The outcome of reading one delimited frame, bounded by `max_frame_size`.
+enum DelimitedRead {
+    Frame(Vec<u8>),
+    /// The connection was closed before any bytes of a new frame arrived.
+    Eof,
+    /// The frame grew past `max_frame_size` before a delimiter showed up.
+    TooLarge,
+}
+
+/// Reads one `delim`-terminated frame from `reader`, giving up as soon as more than
+/// `max_frame_size` bytes of the frame have been buffered. Unlike `BufRead::read_until`,
+/// this checks the cap against each chunk as it arrives rather than after the whole frame
+/// has already been buffered, so a delimiter-less flood can't grow `buf` without bound.
+fn read_delimited_capped(reader: &mut BufReader<TcpStream>, delim: u8, max_frame_size: usize) -> io::Result<DelimitedRead> {
+    let mut buf = Vec::new();
+    loop {
+        let (consumed, frame_complete) = {
+            let available = try!(reader.fill_buf());
+            if available.is_empty() {
+                return Ok(if buf.is_empty() { DelimitedRead::Eof } else { DelimitedRead::Frame(buf) });
+            }
+            match available.iter().position(|&b| b == delim) {
+                Some(pos) => {
+                    buf.extend_from_slice(&available[..pos]);
+                    (pos + 1, true)
+                },
+                None => {
+                    buf.extend_from_slice(available);
+                    (available.len(), false)
+                },
+            }
+        };
+
+        reader.consume(consumed);
+
+        if buf.len() > max_frame_size {
+            return Ok(DelimitedRead::TooLarge);
+        }
+        if frame_complete {
+            return Ok(DelimitedRead::Frame(buf));
+        }
+    }
+}
+
+/// Reads complete frames from `reader` according to `options`, forwarding each one to `ds`
+/// until the connection is closed, a frame exceeds `options.max_frame_size`, or an IO error
+/// occurs.
+fn read_frames(reader: &mut BufReader<TcpStream>, ds: &Sender<io::Result<FrameData>>, options: &Options, metrics: &Metrics) {
+    loop {
+        let frame = match options.framing {
+            Framing::Delimiter(delim) => {
+                match read_delimited_capped(reader, delim, options.max_frame_size) {
+                    Ok(DelimitedRead::Eof) => return,
+                    Ok(DelimitedRead::Frame(buf)) => Ok((buf, SystemTime::now())),
+                    Ok(DelimitedRead::TooLarge) => {
+                        let _ = reader.get_ref().shutdown(Shutdown::Both);
+                        return;
+                    },
+                    Err(e) => Err(e),
+                }
+            },
+            Framing::LengthPrefixed => {
+                let mut len_buf = [0; 4];
+                match reader.read_exact(&mut len_buf) {
+                    Ok(()) => {
+                        let len = ((len_buf[0] as usize) << 24) | ((len_buf[1] as usize) << 16) |
+                                  ((len_buf[2] as usize) << 8) | (len_buf[3] as usize);
+                        if len > options.max_frame_size {
+                            let _ = reader.get_ref().shutdown(Shutdown::Both);
+                            return;
+                        }
+                        let mut buf = vec![0; len];
+                        match reader.read_exact(&mut buf) {
+                            Ok(()) => Ok((buf, SystemTime::now())),
+                            Err(e) => Err(e),
+                        }
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return,
+                    Err(e) => Err(e),
+                }
+            },
+        };
+
+        match frame {
+            Ok((data, received_at)) => {
+                metrics.add_received(data.len());
+
+                let meta = if options.timestamps {
+                    reader.get_ref().peer_addr().ok().map(|addr| (received_at, addr))
+                } else {
+                    None
+                };
+
+                if ds.send(Ok(FrameData { data: data, meta: meta })).is_err() {
+                    return;
+                }
+            },
+            Err(e) => {
+                let _ = ds.send(Err(e));
+                return;
+            },
+        }
+    }
+}